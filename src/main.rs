@@ -7,8 +7,13 @@ use mzdata::{
     Param,
 };
 use std::{
-    fs, io,
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::{self, BufRead, Write},
     path::{Path, PathBuf},
+    sync::{mpsc, OnceLock},
+    thread,
+    time::UNIX_EPOCH,
 };
 
 /// Resolve a USI from the file system.
@@ -17,20 +22,68 @@ use std::{
 /// spectrum is located. MGF files are explicitly ignored, but all other
 /// supported MS data files will be queried in whatever order the file
 /// system lists them.
-#[derive(Debug, clap::Parser)]
+/// The serialization format a resolved spectrum is written out in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum OutputFormat {
+    /// Pretty-printed PROXI JSON (the default)
+    Proxi,
+    /// Mascot Generic Format
+    Mgf,
+    /// mzML
+    Mzml,
+}
+
+#[derive(Debug, Clone, clap::Parser)]
 struct App {
-    #[arg(help = "The USI to search for")]
-    usi: usi::USI,
+    #[arg(help = "The USIs to search for")]
+    usis: Vec<usi::USI>,
+
+    /// Read USIs (one per line) from this file in addition to any positional arguments
+    #[arg(long = "usi-file")]
+    usi_file: Option<PathBuf>,
+
+    /// Number of USIs to resolve concurrently
+    #[arg(short = 'j', long = "jobs", default_value_t = 1)]
+    jobs: usize,
 
     #[arg(short, long, help = "Read only spectrum metadata")]
     metadata_only: bool,
 
+    /// Format to emit resolved spectra in
+    #[arg(long = "output-format", value_enum, default_value_t = OutputFormat::Proxi)]
+    output_format: OutputFormat,
+
+    /// Write output to this path instead of stdout
+    #[arg(short = 'o', long = "output")]
+    output: Option<PathBuf>,
+
     /// Prefixes to visit when resolving datasets
     ///
     /// Suppose we had the USI `mzspec:PXD0012345:data_file:scan:232`, for each
     /// prefix we search `<prefix>/PXD0012345/data_file*`
     #[arg(short = 'p', long = "prefix", default_value = ".")]
     prefixes: Vec<PathBuf>,
+
+    /// PROXI server base URLs to fall back to when no local prefix resolves the USI
+    ///
+    /// Each server is queried as `<URL>/spectra?usi=<usi>&resultType=full`, e.g.
+    /// `https://proteomecentral.proteomexchange.org/api/proxi/v0.1`.
+    #[arg(long = "proxi-server")]
+    proxi_servers: Vec<String>,
+
+    /// Query the PROXI servers before searching the local prefixes
+    #[arg(long)]
+    prefer_remote: bool,
+
+    /// Shared HTTP client, lazily built once and reused across every server and USI so the
+    /// connection pool survives the whole batch.
+    #[arg(skip)]
+    client: OnceLock<reqwest::blocking::Client>,
+
+    /// Consult this on-disk dataset index (see the `index` subcommand) to jump
+    /// straight to the run file instead of enumerating directories
+    #[arg(long = "index-path")]
+    index_path: Option<PathBuf>,
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -38,6 +91,86 @@ struct RepositoryPrefix {
     root: PathBuf,
 }
 
+/// Which reader backend a candidate run path should be opened through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SpectrumBackend {
+    MzData,
+    TimsRust,
+}
+
+impl SpectrumBackend {
+    /// Pick a backend from the path shape: `.d` directories go to `timsrust`, everything else
+    /// to mzdata's `MZReader`.
+    fn for_path(path: &Path) -> Self {
+        let is_d_dir = path.is_dir()
+            && path
+                .extension()
+                .map(|ext| ext.eq_ignore_ascii_case("d"))
+                .unwrap_or_default();
+        if is_d_dir {
+            Self::TimsRust
+        } else {
+            Self::MzData
+        }
+    }
+}
+
+/// Reconstruct a canonical space-delimited nativeID string from its parsed controlled tokens,
+/// e.g. `["controllerType=0", "controllerNumber=1", "scan=5"]` -> `controllerType=0 controllerNumber=1 scan=5`.
+fn native_id_string(items: &[String]) -> String {
+    items.join(" ")
+}
+
+/// Load peaks (centroiding if needed) and annotate the spectrum with its peak count, regardless
+/// of which vendor backend produced it.
+fn annotate_peaks(spec: &mut MultiLayerSpectrum) {
+    if let Err(e) = spec.try_build_peaks() {
+        debug!("Failed to load peaks directly: {e}")
+    }
+    if spec.peaks.is_none() {
+        if let Err(e) = spec.pick_peaks(1.0) {
+            debug!("Failed to pick peaks: {e}");
+        }
+    }
+    // Metadata-only / compact resolution may leave no peaks to count; don't annotate (or panic) then.
+    if spec.peaks.is_none() {
+        debug!("No peaks available to annotate");
+        return;
+    }
+    spec.add_param(
+        Param::builder()
+            .name("number of peaks")
+            .curie(mzdata::curie!(MS:1008040))
+            .value(spec.peaks().len())
+            .build(),
+    );
+    debug!("Found {} peaks", spec.peaks().len());
+}
+
+/// A per-worker cache of opened `MZReader`s, keyed by data file path.
+///
+/// When many USIs resolve against the same run file, opening and indexing it once and reusing the
+/// random-access reader avoids paying the open cost per spectrum.
+#[derive(Default)]
+struct ReaderCache {
+    readers: HashMap<PathBuf, MZReader>,
+}
+
+impl ReaderCache {
+    fn get_or_open(&mut self, path: &Path, load_peaks: bool) -> Option<&mut MZReader> {
+        if !self.readers.contains_key(path) {
+            let mut reader = MZReader::open_path(path)
+                .inspect_err(|e| log::error!("Failed to open `{}`: {e}", path.display()))
+                .ok()?;
+            if !load_peaks {
+                reader.set_detail_level(mzdata::io::DetailLevel::MetadataOnly);
+            }
+            self.readers.insert(path.to_path_buf(), reader);
+        }
+        self.readers.get_mut(path)
+    }
+}
+
 impl RepositoryPrefix {
     fn new(root: PathBuf) -> Self {
         Self { root }
@@ -97,90 +230,865 @@ impl RepositoryPrefix {
         data_path: &Path,
         ident: &usi::USI,
         load_peaks: bool,
+        cache: &mut ReaderCache,
+    ) -> Option<MultiLayerSpectrum> {
+        let mut spec = match SpectrumBackend::for_path(data_path) {
+            SpectrumBackend::MzData => self.get_spectrum_mzdata(data_path, ident, load_peaks, cache),
+            SpectrumBackend::TimsRust => self.get_spectrum_timsrust(data_path, ident),
+        };
+        if let Some(spec) = spec.as_mut() {
+            annotate_peaks(spec);
+        }
+        spec
+    }
+
+    fn get_spectrum_mzdata(
+        &self,
+        data_path: &Path,
+        ident: &usi::USI,
+        load_peaks: bool,
+        cache: &mut ReaderCache,
+    ) -> Option<MultiLayerSpectrum> {
+        let reader = cache.get_or_open(data_path, load_peaks)?;
+        let idx = ident.identifier.as_ref()?;
+        match idx {
+            usi::Identifier::Scan(scan) => {
+                reader.get_spectrum_by_index((*scan).saturating_sub(1) as usize)
+            }
+            usi::Identifier::Index(index) => reader.get_spectrum_by_index((*index) as usize),
+            usi::Identifier::NativeID(items) => {
+                let native_id = native_id_string(items);
+                debug!("Looking up nativeID `{native_id}`");
+                reader.get_spectrum_by_id(&native_id).or_else(|| {
+                    debug!("Direct nativeID lookup missed, scanning spectra by id");
+                    reader.reset();
+                    reader.find(|s| s.id() == native_id)
+                })
+            }
+        }
+    }
+
+    /// Resolve a spectrum out of a directory-structured timsTOF (`.d`) acquisition.
+    ///
+    /// mzdata's `MZReader` only opens regular files, so Bruker runs are read through `timsrust`
+    /// and the selected frame is mapped into a `MultiLayerSpectrum` before the shared peak-count
+    /// annotation and PROXI conversion take over.
+    fn get_spectrum_timsrust(
+        &self,
+        data_path: &Path,
+        ident: &usi::USI,
+    ) -> Option<MultiLayerSpectrum> {
+        use mzdata::spectrum::SpectrumDescription;
+        use mzpeaks::{CentroidPeak, PeakSet};
+
+        let reader = timsrust::readers::SpectrumReader::build()
+            .with_path(data_path)
+            .finalize()
+            .inspect_err(|e| {
+                log::error!("Failed to open timsTOF run `{}`: {e}", data_path.display());
+            })
+            .ok()?;
+
+        let index = match ident.identifier.as_ref()? {
+            usi::Identifier::Scan(scan) => (*scan).saturating_sub(1) as usize,
+            usi::Identifier::Index(index) => *index as usize,
+            usi::Identifier::NativeID(items) => items
+                .iter()
+                .find_map(|tok| tok.strip_prefix("frame=").and_then(|v| v.parse::<usize>().ok()))
+                .map(|frame| frame.saturating_sub(1))?,
+        };
+
+        // `get` panics on an out-of-range index; bound-check so a bad identifier returns `None`
+        // like the mzdata path rather than aborting the process.
+        let n_spectra = reader.len();
+        if index >= n_spectra {
+            log::error!(
+                "timsTOF index {index} out of range ({n_spectra} spectra) in `{}`",
+                data_path.display()
+            );
+            return None;
+        }
+        let frame = reader.get(index);
+        let peaks: PeakSet = frame
+            .mz_values
+            .iter()
+            .zip(frame.intensities.iter())
+            .enumerate()
+            .map(|(i, (mz, inten))| CentroidPeak::new(*mz, *inten as f32, i as u32))
+            .collect();
+
+        let mut descr = SpectrumDescription {
+            id: format!("index={index}"),
+            index,
+            ms_level: 2,
+            ..Default::default()
+        };
+        if let Some(mz) = frame.precursor.mz {
+            descr.precursor = Some(mzdata::spectrum::Precursor {
+                ions: vec![mzdata::spectrum::SelectedIon {
+                    mz,
+                    charge: frame.precursor.charge.map(|c| c as i32),
+                    intensity: frame.precursor.intensity.unwrap_or_default() as f32,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            });
+        }
+        Some(MultiLayerSpectrum::from_peaks_data_levels_and_description(
+            None,
+            Some(peaks),
+            None,
+            descr,
+        ))
+    }
+
+    fn find_spectrum(
+        &self,
+        ident: &usi::USI,
+        load_peaks: bool,
+        cache: &mut ReaderCache,
     ) -> Option<MultiLayerSpectrum> {
-        let mut reader = MZReader::open_path(data_path)
+        let paths: Vec<_> = self
+            .iter_ms_data_files(ident)
             .inspect_err(|e| {
-                log::error!("Failed to open `{}`: {e}", data_path.display());
+                log::error!("Failed to invoke read_dir: {e}");
             })
+            .ok()?
+            .collect();
+        paths
+            .into_iter()
+            .find_map(|p| self.get_spectrum_from_file(&p, ident, load_peaks, cache))
+    }
+}
+
+/// What we record about a single run file so that resolution can skip directory enumeration.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct RunEntry {
+    path: PathBuf,
+    format: String,
+    scan_count: usize,
+    native_id_scheme: Option<String>,
+    /// File modification time in whole seconds since the Unix epoch, used for incremental refresh.
+    mtime: u64,
+    /// File size in bytes, used alongside `mtime` to detect changes.
+    size: u64,
+}
+
+impl RunEntry {
+    /// Build an entry for `path`, opening it once to capture the format and scan count.
+    fn scan(path: &Path) -> Option<Self> {
+        let meta = fs::metadata(path).ok()?;
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let format = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        let mut reader = MZReader::open_path(path)
+            .inspect_err(|e| log::error!("Failed to index `{}`: {e}", path.display()))
             .ok()?;
-        if !load_peaks {
-            reader.set_detail_level(mzdata::io::DetailLevel::MetadataOnly);
+        reader.set_detail_level(mzdata::io::DetailLevel::MetadataOnly);
+        let scan_count = reader.len();
+        let native_id_scheme = reader.get_spectrum_by_index(0).map(|s| {
+            s.id()
+                .split_once('=')
+                .map(|(k, _)| k.to_string())
+                .unwrap_or_else(|| s.id().to_string())
+        });
+        Some(Self {
+            path: path.to_path_buf(),
+            format,
+            scan_count,
+            native_id_scheme,
+            mtime,
+            size: meta.len(),
+        })
+    }
+
+    /// True if the on-disk file still matches the recorded size and mtime.
+    fn is_current(&self) -> bool {
+        match fs::metadata(&self.path) {
+            Ok(meta) => {
+                let mtime = meta
+                    .modified()
+                    .ok()
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default();
+                meta.len() == self.size && mtime == self.mtime
+            }
+            Err(_) => false,
         }
-        if let Some(idx) = ident.identifier.as_ref() {
-            let mut spec = match idx {
-                usi::Identifier::Scan(scan) => {
-                    reader.get_spectrum_by_index((*scan).saturating_sub(1) as usize)
+    }
+}
+
+/// An on-disk cache mapping `dataset -> run_name -> run file metadata`.
+///
+/// Resolution consults the index first so repositories with thousands of runs become a hash
+/// lookup rather than a `read_dir` and linear filename match on every query.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+struct Index {
+    // Several runs can share a stem but differ by format (`sampleA.mzML` vs `sampleA.raw`), so
+    // each run_name maps to every file that carries it rather than a single entry.
+    datasets: BTreeMap<String, BTreeMap<String, Vec<RunEntry>>>,
+}
+
+impl Index {
+    /// Load an index from `path`, returning an empty index if the file does not exist yet.
+    fn load(path: &Path) -> io::Result<Self> {
+        match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)
+    }
+
+    /// Look up a run file for a USI, honouring the recorded file signatures.
+    ///
+    /// Stale entries (whose on-disk size/mtime no longer match) are skipped so a out-of-date
+    /// index degrades to the directory scan rather than returning false negatives. When several
+    /// runs match the prefix the longest (most specific) stem wins, mirroring the descending
+    /// filename-length tie-break in [`RepositoryPrefix::iter_ms_data_files`].
+    fn lookup(&self, ident: &usi::USI) -> Option<&RunEntry> {
+        self.datasets
+            .get(&ident.dataset)?
+            .iter()
+            .filter(|(run_name, _)| run_name.starts_with(&ident.run_name))
+            .flat_map(|(run_name, entries)| {
+                entries
+                    .iter()
+                    .filter(|entry| entry.is_current())
+                    .map(move |entry| (run_name, entry))
+            })
+            .max_by_key(|(run_name, _)| run_name.len())
+            .map(|(_, entry)| entry)
+    }
+
+    /// Walk each prefix once, adding or refreshing entries. Unchanged files are left untouched.
+    fn refresh(&mut self, prefixes: &[PathBuf]) {
+        for prefix in prefixes {
+            let dataset_dirs = match fs::read_dir(prefix) {
+                Ok(it) => it,
+                Err(e) => {
+                    log::error!("Failed to enumerate prefix {}: {e}", prefix.display());
+                    continue;
                 }
-                usi::Identifier::Index(index) => reader.get_spectrum_by_index((*index) as usize),
-                usi::Identifier::NativeID(_items) => todo!(),
             };
-            if let Some(spec) = spec.as_mut() {
-                if let Err(e) = spec.try_build_peaks() {
-                    debug!("Failed to load peaks directly: {e}")
+            for dataset in dataset_dirs.flatten() {
+                if !dataset.path().is_dir() {
+                    continue;
                 }
-                if spec.peaks.is_none() {
-                    spec.pick_peaks(1.0).unwrap();
+                let dataset_name = dataset.file_name().to_string_lossy().into_owned();
+                let runs = match fs::read_dir(dataset.path()) {
+                    Ok(it) => it,
+                    Err(e) => {
+                        log::error!("Failed to enumerate dataset {dataset_name}: {e}");
+                        continue;
+                    }
+                };
+                let entries = self.datasets.entry(dataset_name.clone()).or_default();
+                for run in runs.flatten() {
+                    let path = run.path();
+                    let is_mgf = path
+                        .extension()
+                        .map(|ext| ext.to_ascii_lowercase() == "mgf")
+                        .unwrap_or_default();
+                    if is_mgf {
+                        continue;
+                    }
+                    let run_name = match path.file_stem().and_then(|s| s.to_str()) {
+                        Some(name) => name.to_string(),
+                        None => continue,
+                    };
+                    let run_entries = entries.entry(run_name.clone()).or_default();
+                    if run_entries
+                        .iter()
+                        .any(|entry| entry.path == path && entry.is_current())
+                    {
+                        debug!("Index entry {dataset_name}/{} is up to date", path.display());
+                        continue;
+                    }
+                    if let Some(entry) = RunEntry::scan(&path) {
+                        debug!("Indexed {dataset_name}/{}", path.display());
+                        match run_entries.iter_mut().find(|e| e.path == path) {
+                            Some(slot) => *slot = entry,
+                            None => run_entries.push(entry),
+                        }
+                    }
                 }
-                spec.add_param(
-                    Param::builder()
-                        .name("number of peaks")
-                        .curie(mzdata::curie!(MS:1008040))
-                        .value(spec.peaks().len())
-                        .build(),
-                );
-                debug!("Found {} peaks", spec.peaks().len());
             }
-            spec
-        } else {
-            None
         }
     }
+}
 
-    fn find_spectrum(&self, ident: &usi::USI, load_peaks: bool) -> Option<MultiLayerSpectrum> {
-        self.iter_ms_data_files(&ident)
-            .inspect_err(|e| {
-                log::error!("Failed to invoke read_dir: {e}");
-            })
-            .ok()?
-            .filter_map(|p| self.get_spectrum_from_file(&p, &ident, load_peaks))
-            .next()
+/// Build or refresh the on-disk dataset index.
+#[derive(Debug, clap::Parser)]
+struct IndexApp {
+    /// Prefixes to walk when building the index
+    #[arg(short = 'p', long = "prefix", default_value = ".")]
+    prefixes: Vec<PathBuf>,
+
+    /// Where to read and write the index cache
+    #[arg(long = "index-path", default_value = "usi_index.json")]
+    index_path: PathBuf,
+}
+
+impl IndexApp {
+    fn main(&self) -> io::Result<()> {
+        let mut index = Index::load(&self.index_path)?;
+        index.refresh(&self.prefixes);
+        index.save(&self.index_path)?;
+        let runs: usize = index
+            .datasets
+            .values()
+            .flat_map(BTreeMap::values)
+            .map(Vec::len)
+            .sum();
+        log::info!(
+            "Indexed {} datasets ({runs} runs) into {}",
+            index.datasets.len(),
+            self.index_path.display()
+        );
+        Ok(())
     }
 }
 
-impl App {
+/// Run a long-running PROXI-compliant HTTP service over a shared repository configuration.
+#[derive(Debug, clap::Parser)]
+struct ServeApp {
+    /// Address to bind the HTTP listener to
+    #[arg(long, default_value = "127.0.0.1")]
+    host: String,
+
+    /// Port to bind the HTTP listener to
+    #[arg(long, default_value_t = 8080)]
+    port: u16,
+
+    /// Prefixes to visit when resolving datasets
+    #[arg(short = 'p', long = "prefix", default_value = ".")]
+    prefixes: Vec<PathBuf>,
+
+    /// Consult this on-disk dataset index when resolving
+    #[arg(long = "index-path")]
+    index_path: Option<PathBuf>,
+
+    /// PROXI server base URLs to fall back to when no local prefix resolves the USI
+    #[arg(long = "proxi-server")]
+    proxi_servers: Vec<String>,
+}
+
+impl ServeApp {
     fn main(&self) -> io::Result<()> {
-        let ident: usi::USI = self.usi.clone();
-        debug!("got {ident}");
+        // Build the resolver configuration once and share it across every request. Each
+        // resolution still wraps the configured prefix paths in fresh `RepositoryPrefix` values.
+        let config = App {
+            usis: Vec::new(),
+            usi_file: None,
+            jobs: 1,
+            metadata_only: false,
+            output_format: OutputFormat::Proxi,
+            output: None,
+            prefixes: self.prefixes.clone(),
+            proxi_servers: self.proxi_servers.clone(),
+            prefer_remote: false,
+            client: OnceLock::new(),
+            index_path: self.index_path.clone(),
+        };
+        // Prime the shared HTTP client so every per-request clone reuses one connection pool.
+        config.client.get_or_init(reqwest::blocking::Client::new);
+        // Parse the index once at startup and reuse it for every request.
+        let index = config.load_index();
+        let addr = format!("{}:{}", self.host, self.port);
+        let server = tiny_http::Server::http(&addr)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        log::info!("PROXI server listening on http://{addr}/spectra");
+        for request in server.incoming_requests() {
+            Self::handle(&config, index.as_ref(), request);
+        }
+        Ok(())
+    }
+
+    fn handle(config: &App, index: Option<&Index>, request: tiny_http::Request) {
+        let (code, body) = Self::respond(config, index, request.url());
+        let header = tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+            .expect("valid header");
+        let response = tiny_http::Response::from_string(body)
+            .with_status_code(code)
+            .with_header(header);
+        if let Err(e) = request.respond(response) {
+            log::error!("Failed to write HTTP response: {e}");
+        }
+    }
+
+    /// Resolve a `GET /spectra?usi=<USI>&resultType=full|compact` query into a response body.
+    fn respond(config: &App, index: Option<&Index>, url: &str) -> (u16, String) {
+        let query = url.split_once('?').map(|(_, q)| q).unwrap_or_default();
+        let mut usi_param = None;
+        let mut compact = false;
+        for (key, value) in query.split('&').filter_map(|kv| kv.split_once('=')) {
+            match key {
+                "usi" => usi_param = Some(percent_decode(value)),
+                "resultType" => compact = value.eq_ignore_ascii_case("compact"),
+                _ => {}
+            }
+        }
+
+        let Some(usi_str) = usi_param else {
+            return (400, "{\"error\":\"missing `usi` query parameter\"}".to_string());
+        };
+        let ident = match usi_str.parse::<usi::USI>() {
+            Ok(ident) => ident,
+            Err(e) => return (400, format!("{{\"error\":\"invalid USI: {e}\"}}")),
+        };
+
+        let mut request_config = config.clone();
+        request_config.metadata_only = compact;
+        let mut cache = ReaderCache::default();
+        match request_config.resolve(&ident, &mut cache, index) {
+            Some(spec) => (
+                200,
+                serde_json::to_string(&vec![spec]).unwrap_or_else(|_| "[]".to_string()),
+            ),
+            None => {
+                log::error!("Failed to locate spectrum for `{ident}`");
+                (404, "[]".to_string())
+            }
+        }
+    }
+}
+
+/// Decode `%XX` escapes and `+` in a URL query component.
+fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                match u8::from_str_radix(&input[i + 1..i + 3], 16) {
+                    Ok(byte) => {
+                        out.push(byte);
+                        i += 3;
+                    }
+                    Err(_) => {
+                        out.push(b'%');
+                        i += 1;
+                    }
+                }
+            }
+            b'+' => {
+                out.push(b' ');
+                i += 1;
+            }
+            b => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+impl App {
+    /// Load the dataset index once, if `--index-path` is configured, so callers can share it
+    /// across every USI rather than re-deserializing it per resolution.
+    fn load_index(&self) -> Option<Index> {
+        let path = self.index_path.as_ref()?;
+        match Index::load(path) {
+            Ok(index) => Some(index),
+            Err(e) => {
+                log::error!("Failed to load index {}: {e}", path.display());
+                None
+            }
+        }
+    }
 
-        let spec = self
-            .prefixes
+    fn find_local(
+        &self,
+        ident: &usi::USI,
+        cache: &mut ReaderCache,
+        index: Option<&Index>,
+    ) -> Option<MultiLayerSpectrum> {
+        if let Some(index) = index {
+            if let Some(entry) = index.lookup(ident) {
+                debug!("Index hit: {}", entry.path.display());
+                if let Some(spec) = RepositoryPrefix::new(PathBuf::new()).get_spectrum_from_file(
+                    &entry.path,
+                    ident,
+                    !self.metadata_only,
+                    cache,
+                ) {
+                    return Some(spec);
+                }
+                debug!("Index hit failed to yield a spectrum, falling back to scan");
+            } else {
+                debug!("Index miss for `{ident}`, falling back to directory scan");
+            }
+        }
+        self.prefixes
             .iter()
             .cloned()
             .map(RepositoryPrefix::new)
-            .filter_map(|p| {
+            .find_map(|p| {
                 debug!("Visiting {p:?}");
-                p.find_spectrum(&ident, !self.metadata_only)
+                p.find_spectrum(ident, !self.metadata_only, cache)
             })
-            .next();
+    }
+
+    /// Query each configured PROXI server in turn, returning the first spectrum hit.
+    ///
+    /// A failing endpoint is logged and skipped rather than aborting the whole search, so a
+    /// single unreachable server does not hide a hit on another.
+    fn find_remote(&self, ident: &usi::USI) -> Option<PROXISpectrum> {
+        let usi = ident.to_string();
+        let client = self.client.get_or_init(reqwest::blocking::Client::new);
+        self.proxi_servers.iter().find_map(|base| {
+            let url = format!("{}/spectra", base.trim_end_matches('/'));
+            debug!("Querying PROXI server {url} for `{usi}`");
+            match client
+                .get(&url)
+                .query(&[("usi", usi.as_str()), ("resultType", "full")])
+                .send()
+                .and_then(|r| r.error_for_status())
+                .and_then(|r| r.json::<Vec<PROXISpectrum>>())
+            {
+                Ok(hits) => hits.into_iter().next(),
+                Err(e) => {
+                    log::error!("PROXI server {base} failed for `{usi}`: {e}");
+                    None
+                }
+            }
+        })
+    }
 
-        if let Some(spec) = spec {
-            let mut proxi_spec = PROXISpectrum::from(&spec);
-            proxi_spec.usi = Some(ident.clone());
-            let repr = serde_json::to_string_pretty(&proxi_spec)?;
-            println!("{repr}");
+    fn resolve(
+        &self,
+        ident: &usi::USI,
+        cache: &mut ReaderCache,
+        index: Option<&Index>,
+    ) -> Option<PROXISpectrum> {
+        let local = |cache: &mut ReaderCache| {
+            self.find_local(ident, cache, index).map(|spec| {
+                let mut proxi_spec = PROXISpectrum::from(&spec);
+                proxi_spec.usi = Some(ident.clone());
+                proxi_spec
+            })
+        };
+        if self.prefer_remote {
+            self.find_remote(ident).or_else(|| local(cache))
+        } else {
+            local(cache).or_else(|| self.find_remote(ident))
+        }
+    }
+
+    /// Collect the USIs to resolve from the positional list, `--usi-file`, and stdin.
+    fn collect_usis(&self) -> io::Result<Vec<usi::USI>> {
+        let mut usis = self.usis.clone();
+        if let Some(path) = self.usi_file.as_ref() {
+            let file = fs::File::open(path)?;
+            parse_usi_lines(io::BufReader::new(file), &mut usis);
+        }
+        // If nothing was supplied on the command line or via a file, drain stdin so the tool can
+        // sit at the end of a pipe.
+        if usis.is_empty() {
+            parse_usi_lines(io::stdin().lock(), &mut usis);
+        }
+        Ok(usis)
+    }
+
+    fn main(&self) -> io::Result<()> {
+        let usis = self.collect_usis()?;
+        if usis.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "No USIs supplied on the command line, via --usi-file, or on stdin",
+            ));
+        }
+        debug!("Resolving {} USI(s)", usis.len());
+
+        // Parse the on-disk index once and share it across all workers rather than
+        // re-deserializing it per USI.
+        let index = self.load_index();
+        let index = index.as_ref();
+
+        match self.output_format {
+            OutputFormat::Proxi => {
+                let results =
+                    self.run_jobs(&usis, |app, ident, cache| app.resolve(ident, cache, index));
+                let found: Vec<_> = results.into_iter().flatten().collect();
+                let repr = serde_json::to_string_pretty(&found)?;
+                self.write_text(&repr)?;
+                self.report_missing(usis.len(), found.len())
+            }
+            OutputFormat::Mgf | OutputFormat::Mzml => {
+                let results = self.run_jobs(&usis, |app, ident, cache| {
+                    let local = app.find_local(ident, cache, index);
+                    if local.is_none() && !app.proxi_servers.is_empty() {
+                        // MGF/mzML export operates on the `MultiLayerSpectrum`, which the remote
+                        // PROXI path can't supply; flag the divergence from PROXI output without
+                        // issuing (and discarding) a real network request.
+                        log::warn!(
+                            "`{ident}` not found locally; remote PROXI hits cannot be exported to \
+                             MGF/mzML; use --output-format proxi to include it"
+                        );
+                    }
+                    local.map(|mut spec| {
+                        // Title the exported spectrum with the USI it was resolved from.
+                        spec.description_mut().id = ident.to_string();
+                        spec
+                    })
+                });
+                let found: Vec<_> = results.into_iter().flatten().collect();
+                let n = found.len();
+                self.write_spectra(found)?;
+                self.report_missing(usis.len(), n)
+            }
+        }
+    }
+
+    fn report_missing(&self, total: usize, found: usize) -> io::Result<()> {
+        if found == total {
             Ok(())
         } else {
-            log::error!("Failed to locate spectrum for `{ident}`");
             Err(io::Error::new(
                 io::ErrorKind::NotFound,
-                format!("Failed to locate spectrum for `{ident}`"),
+                format!("Failed to locate {} of {total} spectra", total - found),
             ))
         }
     }
+
+    /// Write a text payload to `--output` if set, otherwise to stdout.
+    fn write_text(&self, text: &str) -> io::Result<()> {
+        match self.output.as_ref() {
+            Some(path) => fs::write(path, text),
+            None => {
+                println!("{text}");
+                Ok(())
+            }
+        }
+    }
+
+    /// Serialize resolved spectra to a single MGF or mzML stream using mzdata's writers.
+    fn write_spectra(&self, spectra: Vec<MultiLayerSpectrum>) -> io::Result<()> {
+        let sink: Box<dyn Write> = match self.output.as_ref() {
+            Some(path) => Box::new(io::BufWriter::new(fs::File::create(path)?)),
+            None => Box::new(io::stdout().lock()),
+        };
+        match self.output_format {
+            OutputFormat::Mgf => {
+                let mut writer = mzdata::io::mgf::MGFWriter::new(sink);
+                for spec in &spectra {
+                    writer.write(spec)?;
+                }
+                writer.close()?;
+            }
+            OutputFormat::Mzml => {
+                let mut writer = mzdata::io::mzml::MzMLWriter::new(sink);
+                for spec in &spectra {
+                    writer.write(spec)?;
+                }
+                writer.close()?;
+            }
+            OutputFormat::Proxi => unreachable!("PROXI output is written as JSON"),
+        }
+        Ok(())
+    }
+
+    /// Resolve every USI across a bounded pool of worker threads, preserving input order.
+    ///
+    /// Each worker owns one USI at a time, walks the prefixes with its own [`ReaderCache`], and
+    /// pushes the `(index, Option<T>)` back through a channel. Incremental progress is written to stderr
+    /// so long result tables report liveness while collecting.
+    fn run_jobs<T, F>(&self, usis: &[usi::USI], resolve: F) -> Vec<Option<T>>
+    where
+        T: Send,
+        F: Fn(&App, &usi::USI, &mut ReaderCache) -> Option<T> + Sync,
+    {
+        let jobs = self.jobs.max(1).min(usis.len().max(1));
+        let total = usis.len();
+        let (work_tx, work_rx) = mpsc::channel::<(usize, usi::USI)>();
+        let work_rx = std::sync::Arc::new(std::sync::Mutex::new(work_rx));
+        let (result_tx, result_rx) = mpsc::channel::<(usize, Option<T>)>();
+
+        for (i, ident) in usis.iter().enumerate() {
+            work_tx.send((i, ident.clone())).expect("work channel open");
+        }
+        drop(work_tx);
+
+        let mut results: Vec<Option<T>> = (0..total).map(|_| None).collect();
+        let resolve = &resolve;
+        thread::scope(|scope| {
+            for _ in 0..jobs {
+                let work_rx = work_rx.clone();
+                let result_tx = result_tx.clone();
+                scope.spawn(move || {
+                    let mut cache = ReaderCache::default();
+                    loop {
+                        let job = work_rx.lock().expect("work mutex").recv();
+                        let Ok((i, ident)) = job else { break };
+                        let found = resolve(self, &ident, &mut cache);
+                        if found.is_none() {
+                            log::error!("Failed to locate spectrum for `{ident}`");
+                        }
+                        result_tx.send((i, found)).expect("result channel open");
+                    }
+                });
+            }
+            drop(result_tx);
+
+            let mut done = 0usize;
+            let mut failures = 0usize;
+            for (i, found) in result_rx {
+                done += 1;
+                if found.is_none() {
+                    failures += 1;
+                }
+                results[i] = found;
+                eprint!("\rResolved {done}/{total} ({failures} failed)");
+            }
+            eprintln!();
+        });
+        results
+    }
+}
+
+/// Parse one whitespace-trimmed USI per non-empty line, logging and skipping malformed entries.
+fn parse_usi_lines<R: BufRead>(reader: R, usis: &mut Vec<usi::USI>) {
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                log::error!("Failed to read USI line: {e}");
+                continue;
+            }
+        };
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+        match trimmed.parse::<usi::USI>() {
+            Ok(usi) => usis.push(usi),
+            Err(e) => log::error!("Failed to parse USI `{trimmed}`: {e}"),
+        }
+    }
+}
+
+/// Resolve Universal Spectrum Identifiers against a local MS data repository.
+#[derive(Debug, clap::Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Resolve a USI from the file system (and optional PROXI servers)
+    Resolve(App),
+    /// Build or refresh the on-disk dataset index
+    Index(IndexApp),
+    /// Serve the PROXI HTTP interface over a local repository
+    Serve(ServeApp),
 }
 
 fn main() -> io::Result<()> {
     env_logger::init();
-    let args = App::parse();
-    args.main()
+    match Cli::parse().command {
+        Command::Resolve(app) => app.main(),
+        Command::Index(app) => app.main(),
+        Command::Serve(app) => app.main(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_decode_escapes_and_plus() {
+        assert_eq!(percent_decode("mzspec%3APXD000001%3Arun"), "mzspec:PXD000001:run");
+        assert_eq!(percent_decode("a+b"), "a b");
+        assert_eq!(percent_decode("plain"), "plain");
+        // A trailing, malformed escape is left verbatim rather than dropped.
+        assert_eq!(percent_decode("100%"), "100%");
+    }
+
+    #[test]
+    fn native_id_string_is_space_delimited() {
+        let items = vec![
+            "controllerType=0".to_string(),
+            "controllerNumber=1".to_string(),
+            "scan=5".to_string(),
+        ];
+        assert_eq!(native_id_string(&items), "controllerType=0 controllerNumber=1 scan=5");
+    }
+
+    #[test]
+    fn parse_usi_lines_skips_blank_and_invalid() {
+        let input = "mzspec:PXD000001:run:scan:1\n\n   \nnot a usi\nmzspec:PXD000001:run:scan:2\n";
+        let mut usis = Vec::new();
+        parse_usi_lines(input.as_bytes(), &mut usis);
+        assert_eq!(usis.len(), 2);
+    }
+
+    /// Build a `RunEntry` whose recorded signature matches the file currently at `path`.
+    fn current_entry(path: &Path) -> RunEntry {
+        let meta = fs::metadata(path).unwrap();
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        RunEntry {
+            path: path.to_path_buf(),
+            format: "mzml".to_string(),
+            scan_count: 0,
+            native_id_scheme: None,
+            mtime,
+            size: meta.len(),
+        }
+    }
+
+    fn usi_for(text: &str) -> usi::USI {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn lookup_prefers_longest_match_and_skips_stale() {
+        let dir = std::env::temp_dir().join("usi_extract_lookup_test");
+        fs::create_dir_all(&dir).unwrap();
+        let short = dir.join("sample.mzML");
+        let long = dir.join("sample_rep2.mzML");
+        fs::write(&short, b"short").unwrap();
+        fs::write(&long, b"longer run").unwrap();
+
+        let mut runs = BTreeMap::new();
+        runs.insert("sample".to_string(), vec![current_entry(&short)]);
+        runs.insert("sample_rep2".to_string(), vec![current_entry(&long)]);
+        let mut datasets = BTreeMap::new();
+        datasets.insert("PXD000001".to_string(), runs);
+        let index = Index { datasets };
+
+        // Both stems start with `sample`; the longest (most specific) wins, mirroring the scan.
+        let hit = index.lookup(&usi_for("mzspec:PXD000001:sample:scan:1")).unwrap();
+        assert_eq!(hit.path, long);
+
+        // Changing a file invalidates its entry, so the lookup falls through to the fresh one.
+        fs::write(&long, b"changed on disk").unwrap();
+        let hit = index.lookup(&usi_for("mzspec:PXD000001:sample:scan:1")).unwrap();
+        assert_eq!(hit.path, short);
+
+        fs::remove_dir_all(&dir).ok();
+    }
 }